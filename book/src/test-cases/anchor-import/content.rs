@@ -0,0 +1,9 @@
+fn before() {}
+
+// ANCHOR: example
+fn example() {
+    println!("hi");
+}
+// ANCHOR_END: example
+
+fn after() {}
@@ -0,0 +1,7 @@
+fn wrapper() {
+    // @book start demo
+    fn demo() {
+        println!("hello");
+    }
+    // @book end demo
+}
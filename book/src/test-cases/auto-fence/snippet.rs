@@ -0,0 +1,5 @@
+// @book start demo
+fn demo() {
+    println!("hello");
+}
+// @book end demo
@@ -0,0 +1,14 @@
+// @book start shared
+fn first() {
+    println!("first");
+}
+// @book end shared
+
+// Unrelated code between the two blocks that reuse the same tag name.
+fn unrelated() {}
+
+// @book start shared
+fn second() {
+    println!("second");
+}
+// @book end shared
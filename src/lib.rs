@@ -11,13 +11,24 @@ use log::*;
 use mdbook::book::{Book, Chapter};
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
 use mdbook::BookItem;
+use rayon::prelude::*;
 use regex::Regex;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 // Originally tried using "\" but ran into issues with mdbook seemingly stripping it.
 // Probably because it also uses "\" to escape it's imports
 static _ESCAPE_CHAR: &'static str = "/";
 
+/// Raw file contents keyed by canonicalized path, shared across every chapter being
+/// resolved so that a file imported by many chapters is only ever read from disk once.
+///
+/// Each entry is its own `Mutex`, held across the read that fills it - so two chapters
+/// resolving the same file concurrently have the second one block on the first's disk read
+/// and reuse its result, rather than both missing the outer map and reading the file twice.
+type FileCache = Arc<Mutex<HashMap<PathBuf, Arc<Mutex<Option<String>>>>>>;
+
 /// The pre-processor that powers the mdbook-bookimport plugin
 pub struct Bookimport;
 
@@ -26,8 +37,36 @@ impl Preprocessor for Bookimport {
         "mdbook-bookimport"
     }
 
+    /// Only advertise support for renderers listed under a
+    /// `[preprocessor.bookimport] renderer = [...]` array, if one was configured.
+    ///
+    /// This runs as `mdbook-bookimport supports <renderer>`, which mdbook invokes directly - there's
+    /// no `PreprocessorContext` yet, since no book has been piped in. So instead of reading
+    /// `ctx.config` we load `book.toml` straight from the current directory, which is where
+    /// mdbook runs this subcommand from and is the same file `ctx.config` is built from once
+    /// preprocessing actually starts.
+    fn supports_renderer(&self, renderer: &str) -> bool {
+        let config = match mdbook::Config::from_disk("book.toml") {
+            Ok(config) => config,
+            // No readable book.toml to consult - fall back to the previous behavior of
+            // supporting every renderer.
+            Err(_) => return true,
+        };
+
+        match allowed_renderers(&config) {
+            Some(allowed) => allowed.iter().any(|allowed| allowed == renderer),
+            None => true,
+        }
+    }
+
     /// Given a book (usually from stdin) process all of the chapters and replace
     /// any #bookimport's with the content that you're importing.
+    ///
+    /// Every chapter's bookimports are first collected into one flat work list and resolved
+    /// together in parallel, then applied back into their chapters sequentially. Resolving
+    /// at the import level (rather than one chapter at a time) is what keeps a single
+    /// chapter with hundreds of imports from being bottlenecked on resolving them one file
+    /// read at a time, which chapter-level parallelism alone wouldn't help with.
     fn run(
         &self,
         ctx: &PreprocessorContext,
@@ -35,64 +74,271 @@ impl Preprocessor for Bookimport {
     ) -> Result<Book, mdbook::errors::Error> {
         debug!("Running `run` method in bookimport Preprocessor trait impl");
 
+        // `supports_renderer` can't see `ctx.renderer`, so as a second line of defense we
+        // also refuse to touch the book here if the active renderer isn't in the allowlist.
+        if let Some(allowed) = allowed_renderers(&ctx.config) {
+            if !allowed.iter().any(|allowed| allowed == &ctx.renderer) {
+                debug!(
+                    "Renderer `{}` is not listed under preprocessor.bookimport.renderer, passing the book through untouched",
+                    ctx.renderer
+                );
+                return Ok(book);
+            }
+        }
+
         let book_src_dir = ctx.root.join(&ctx.config.book.src);
+        let cache: FileCache = Arc::new(Mutex::new(HashMap::new()));
+        let auto_fence_default = auto_fence_enabled(&ctx.config);
+
+        let mut chapter_imports = Vec::new();
+        collect_chapter_imports(&book.sections, &book_src_dir, &mut Vec::new(), &mut chapter_imports);
+
+        // Flatten every chapter's bookimports into one work list, so a chapter with
+        // hundreds of imports gets the same import-level concurrency as a book with many
+        // imports spread across many small chapters.
+        let mut flat_imports = Vec::new();
+        for (chapter_index, imports) in chapter_imports.iter().enumerate() {
+            for simport_index in 0..imports.simports.len() {
+                flat_imports.push((chapter_index, simport_index));
+            }
+        }
+
+        let resolved: Vec<(usize, usize, String)> = flat_imports
+            .par_iter()
+            .map(|&(chapter_index, simport_index)| {
+                let imports = &chapter_imports[chapter_index];
+                let simport = &imports.simports[simport_index];
+
+                // Each top level #bookimport gets its own resolution stack so that
+                // unrelated imports don't trip each other's circular import detection.
+                let mut import_stack = Vec::new();
+
+                let new_content = simport
+                    .read_content_between_tags(&imports.chapter_dir, &mut import_stack, &cache)
+                    .map_err(|cause| located_error(&simport.host_chapter_name, simport, cause))?;
+
+                Ok((chapter_index, simport_index, new_content))
+            })
+            .collect::<mdbook::errors::Result<Vec<_>>>()?;
+
+        let mut raw_contents: Vec<Vec<String>> = chapter_imports
+            .iter()
+            .map(|imports| vec![String::new(); imports.simports.len()])
+            .collect();
+        for (chapter_index, simport_index, new_content) in resolved {
+            raw_contents[chapter_index][simport_index] = new_content;
+        }
 
-        for section in book.sections.iter_mut() {
-            process_chapter(section, &book_src_dir)?;
+        // Apply the already-resolved content back into each chapter sequentially - this
+        // part is cheap (string replacement, no file IO) so there's nothing to gain from
+        // doing it in parallel, and it needs `&mut Chapter` anyway.
+        for (imports, raw_contents) in chapter_imports.iter().zip(raw_contents.iter()) {
+            let chapter = chapter_at_mut(&mut book.sections, &imports.path);
+            apply_resolved_imports(chapter, &imports.simports, raw_contents, auto_fence_default);
         }
 
         Ok(book)
     }
 }
 
-/// Process a chapter in an mdbook.
-///
-/// Namely - replace all #bookimport calls with the content that it was trying to import.
-///
-/// If the chapter has subchapters they will also be processed recursively.
-fn process_chapter(book_item: &mut BookItem, book_src_dir: &PathBuf) -> mdbook::errors::Result<()> {
-    if let BookItem::Chapter(ref mut chapter) = book_item {
-        debug!("Processing chapter {}", chapter.name);
-
-        // The full path within the filesystem to the directory that holds the mdbook's
-        // SUMMARY.md file
-        //
-        // /path/to/.../my-mdbook
-        let chapter_dir = chapter
-            .path
-            .parent()
-            .map(|dir| book_src_dir.join(dir))
-            .expect("All book items have a parent");
+/// Read the renderers listed under `preprocessor.bookimport.renderer` in a book's config, if
+/// any were configured. A missing key means "no restriction", preserving the pre-existing
+/// behavior of supporting every renderer.
+fn allowed_renderers(config: &mdbook::Config) -> Option<Vec<String>> {
+    config
+        .get("preprocessor.bookimport.renderer")
+        .and_then(|value| value.clone().try_into::<Vec<String>>().ok())
+}
 
-        let mut content = chapter.content.clone();
+/// Whether `preprocessor.bookimport.auto-fence` was turned on, making every bookimport that
+/// sits on its own line (and isn't already inside a fence) get wrapped in one automatically.
+/// Individual imports can opt in the same way via `{{#bookimport foo.rs@tag as code}}`
+/// regardless of this setting.
+fn auto_fence_enabled(config: &mdbook::Config) -> bool {
+    config
+        .get("preprocessor.bookimport.auto-fence")
+        .and_then(|value| value.clone().try_into::<bool>().ok())
+        .unwrap_or(false)
+}
 
-        let simports = BookImport::find_unescaped_bookimports(chapter);
+/// One chapter's worth of bookimports, collected up front so every chapter's imports can be
+/// thrown into one shared parallel work list instead of being resolved chapter by chapter.
+struct ChapterImports {
+    /// The index path down `book.sections` (and, through nested chapters, their
+    /// `sub_items`) that leads back to this chapter - used by `chapter_at_mut` to find the
+    /// chapter again once its imports have been resolved.
+    path: Vec<usize>,
+    /// The chapter's own directory, used to resolve its bookimports' relative paths.
+    chapter_dir: PathBuf,
+    /// The bookimports found directly in this chapter (not its sub-chapters, which get
+    /// their own `ChapterImports` entry).
+    simports: Vec<BookImport>,
+}
 
-        // Iterate backwards through the simports so that we start by replacing the imports
-        // that are lower in the file first.
-        //
-        // This ensures that as we replace simports we aren't throwing off the start and end
-        // indices of other simports.
-        for simport in simports.iter().rev() {
-            let new_content = match simport.read_content_between_tags(&chapter_dir) {
-                Ok(new_content) => new_content,
-                Err(err) => panic!("Error reading content for bookimport: {:#?}", err),
-            };
+/// Walk a book's tree of `BookItem`s and record every chapter's directory and its own
+/// bookimports (not its sub-chapters', which get their own entry) into `out`, without
+/// touching the tree itself.
+///
+/// This only reads the book, so collecting from every chapter (including nested ones)
+/// doesn't run into the borrow-checker trouble that collecting `&mut Chapter`s into one
+/// shared `Vec` would - a `Chapter` owns its `sub_items`, so a `&mut Chapter` and `&mut`
+/// borrows reached through its own `sub_items` field can never be alive at the same time in
+/// safe Rust.
+fn collect_chapter_imports(
+    items: &[BookItem],
+    book_src_dir: &Path,
+    path: &mut Vec<usize>,
+    out: &mut Vec<ChapterImports>,
+) {
+    for (index, item) in items.iter().enumerate() {
+        if let BookItem::Chapter(chapter) = item {
+            path.push(index);
+            collect_chapter_imports(&chapter.sub_items, book_src_dir, path, out);
+
+            // A draft chapter (`chapter.path` is `None`) has no source file and thus no
+            // content of its own to scan for bookimports - nothing to collect for it.
+            let chapter_dir = chapter
+                .path
+                .as_ref()
+                .and_then(|path| path.parent())
+                .map(|dir| book_src_dir.join(dir));
+
+            if let Some(chapter_dir) = chapter_dir {
+                out.push(ChapterImports {
+                    path: path.clone(),
+                    chapter_dir,
+                    simports: BookImport::find_unescaped_bookimports(chapter),
+                });
+            }
 
-            // Replace the #bookimport in the chapter with the contents that we were
-            // trying to impor.
-            content = content.replace(simport.full_simport_text, &new_content);
+            path.pop();
         }
+    }
+}
 
-        chapter.content = content;
-
-        // Process all of the chapters within this chapter
-        for sub_item in chapter.sub_items.iter_mut() {
-            process_chapter(sub_item, book_src_dir)?;
+/// Find the chapter that a `ChapterImports::path` (as produced by `collect_chapter_imports`)
+/// points at.
+fn chapter_at_mut<'a>(items: &'a mut [BookItem], path: &[usize]) -> &'a mut Chapter {
+    let (index, rest) = path.split_first().expect("a chapter path is never empty");
+
+    match &mut items[*index] {
+        BookItem::Chapter(chapter) => {
+            if rest.is_empty() {
+                chapter
+            } else {
+                chapter_at_mut(&mut chapter.sub_items, rest)
+            }
         }
+        _ => unreachable!("a chapter path must only index Chapters"),
     }
+}
+
+/// Replace `chapter`'s own bookimports with their already-resolved `raw_contents` (one per
+/// entry in `simports`, in the same order), applying auto-fencing the same way a freshly
+/// resolved bookimport would.
+///
+/// `auto_fence_default` is the book-wide `preprocessor.bookimport.auto-fence` setting; each
+/// bookimport additionally gets its own say via the per-import `as code` flag.
+fn apply_resolved_imports(
+    chapter: &mut Chapter,
+    simports: &[BookImport],
+    raw_contents: &[String],
+    auto_fence_default: bool,
+) {
+    debug!("Applying resolved bookimports to chapter {}", chapter.name);
+
+    let mut content = chapter.content.clone();
+
+    // Iterate backwards through the simports so that we start by replacing the imports
+    // that are lower in the file first.
+    //
+    // This ensures that as we replace simports we aren't throwing off the start and end
+    // indices of other simports.
+    for (simport, new_content) in simports.iter().zip(raw_contents).rev() {
+        let wants_fence = simport.as_code || auto_fence_default;
+        let new_content = if wants_fence && simport.sits_on_own_line(&content) {
+            if is_inside_existing_fence(&content, simport.start) {
+                // Already between a fence the chapter wrote itself - just trim the
+                // snippet's own trailing newline the same way `wrap_in_fence` does, so we
+                // don't leave a blank line dangling before the closing ``` that's already
+                // there.
+                new_content.trim_end_matches('\n').to_string()
+            } else {
+                wrap_in_fence(new_content, &simport.file)
+            }
+        } else {
+            new_content.clone()
+        };
+
+        // Splice in the contents we were trying to import at this simport's own byte
+        // range, rather than text-replacing every occurrence of `full_simport_text` - if
+        // the exact same `{{#bookimport ...}}` markup appears more than once in a chapter
+        // (say, the same snippet quoted in two sections), a text-based replace would
+        // rewrite both occurrences at once and leave every other simport's `start`/`end`
+        // pointing past the end of the now-resized `content`. Splicing only this range
+        // keeps everything before `simport.start` byte-for-byte identical to the original
+        // content, which is what the reverse iteration order above relies on.
+        content.replace_range(simport.start..simport.end, &new_content);
+    }
+
+    chapter.content = content;
+}
 
-    Ok(())
+/// Whether `pos` (a byte offset) falls inside an already-open ``` fence, so we don't wrap a
+/// bookimport's content in a second, nested fence.
+fn is_inside_existing_fence(content: &str, pos: usize) -> bool {
+    content[..pos].matches("```").count() % 2 == 1
+}
+
+/// Wrap `content` in a fenced code block, with the language inferred from `file`'s
+/// extension and common leading indentation trimmed off.
+fn wrap_in_fence(content: &str, file: &Path) -> String {
+    let language = file
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(language_for_extension)
+        .unwrap_or("");
+
+    let trimmed = trim_common_indent(content);
+
+    format!("```{}\n{}\n```", language, trimmed.trim_end_matches('\n'))
+}
+
+/// A small extension -> Markdown fence language map, covering the kinds of files a book is
+/// most likely to import snippets from.
+fn language_for_extension(extension: &str) -> Option<&'static str> {
+    Some(match extension {
+        "rs" => "rust",
+        "toml" => "toml",
+        "css" => "css",
+        "js" => "javascript",
+        "ts" => "typescript",
+        "py" => "python",
+        "sh" => "bash",
+        "md" => "markdown",
+        "json" => "json",
+        "html" => "html",
+        "yml" | "yaml" => "yaml",
+        _ => return None,
+    })
+}
+
+/// Strip the smallest common leading whitespace shared by every non-blank line, so a snippet
+/// pulled out of an indented block in its source file doesn't show up over-indented once
+/// spliced into a chapter.
+fn trim_common_indent(content: &str) -> String {
+    let common_indent = content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    content
+        .lines()
+        .map(|line| line.get(common_indent..).unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
 /// # Example
@@ -113,12 +359,18 @@ fn process_chapter(book_item: &mut BookItem, book_src_dir: &PathBuf) -> mdbook::
 /// ```
 ///
 /// The doc comments on the struct fields refer to this bookimport
-#[derive(Debug, PartialEq)]
-struct BookImport<'a> {
-    /// The book chapter that this #bookimport was found in
+///
+/// A `BookImport` is an owned snapshot of a single `{{#bookimport ...}}` occurrence. It's
+/// intentionally decoupled from the `Chapter` it was parsed out of (beyond a name used for
+/// debugging) so that the same type can describe imports nested inside an already-imported
+/// snippet, not just ones found directly in a chapter's Markdown, and so it can be shipped
+/// across threads while chapters are resolved in parallel.
+#[derive(Debug, PartialEq, Clone)]
+struct BookImport {
+    /// The name of the book chapter that this #bookimport was (transitively) found in
     ///
     /// introduction.md
-    host_chapter: &'a Chapter,
+    host_chapter_name: String,
     /// The filepath relative to the chapter
     ///
     /// ../book.toml
@@ -126,22 +378,79 @@ struct BookImport<'a> {
     /// The text of this bookimport in the host_chapter
     ///
     /// {{ #bookimport some-file.txt@some-tag }}
-    full_simport_text: &'a str,
-    /// Tags after the characters after an `@` symbol. When importing from a file
-    /// Bookimport will pull all text before and after the `@tag`
-    ///
-    /// Some(book-section)
-    tag: &'a str,
-    /// Where in the chapter's bytes does this bookimport start?
+    full_simport_text: String,
+    /// What to pull out of `file`: either a named tag, or a line range. See
+    /// `ImportSelector` for the two accepted `@...` syntaxes.
+    selector: ImportSelector,
+    /// Whether this import opted into auto-fencing via `{{#bookimport foo.rs@tag as code}}`,
+    /// regardless of the book-wide `preprocessor.bookimport.auto-fence` setting.
+    as_code: bool,
+    /// Where in the host content's bytes does this bookimport start?
     start: usize,
-    /// Where in the chapter's bytes does this bookimport end?
+    /// Where in the host content's bytes does this bookimport end?
     end: usize,
 }
 
+impl BookImport {
+    /// Whether this bookimport is the only non-whitespace content on its line in `content`,
+    /// a prerequisite for auto-fencing it - wrapping an inline `{{#bookimport}}` (one that
+    /// shares a line with other text) in a fence would mangle the surrounding prose.
+    fn sits_on_own_line(&self, content: &str) -> bool {
+        let before_is_blank = content[..self.start]
+            .rfind('\n')
+            .map(|idx| content[idx + 1..self.start].trim().is_empty())
+            .unwrap_or_else(|| content[..self.start].trim().is_empty());
+
+        let after_is_blank = content[self.end..]
+            .find('\n')
+            .map(|idx| content[self.end..self.end + idx].trim().is_empty())
+            .unwrap_or_else(|| content[self.end..].trim().is_empty());
+
+        before_is_blank && after_is_blank
+    }
+}
+
+/// What a bookimport pulls out of the file it points at, i.e. the part after the `@`.
+///
+/// -> `@some-tag` is a named `Tag`, delimited by `@book start`/`@book end` (or mdbook's own
+/// `ANCHOR:`/`ANCHOR_END:`) comments in the target file.
+///
+/// -> `@:10:20` (or `@:10:` / `@::20`) is a `LineRange`, an inclusive 1-indexed slice of the
+/// target file's lines, mirroring mdbook's own `{{#include file.rs:10:20}}` syntax. Either
+/// bound may be omitted to mean "from the first line" / "through the last line".
+#[derive(Debug, PartialEq, Clone)]
+enum ImportSelector {
+    /// A named `@book`/`ANCHOR` tag.
+    Tag(String),
+    /// An inclusive, 1-indexed `start..=end` line range.
+    LineRange {
+        /// The first line to include, or `None` for "from the start of the file".
+        start: Option<usize>,
+        /// The last line to include, or `None` for "through the end of the file".
+        end: Option<usize>,
+    },
+}
+
+impl ImportSelector {
+    /// A short, human readable label for this selector, used in error messages and to key
+    /// the circular-import resolution stack.
+    fn label(&self) -> String {
+        match self {
+            ImportSelector::Tag(tag) => tag.clone(),
+            ImportSelector::LineRange { start, end } => format!(
+                ":{}:{}",
+                start.map(|n| n.to_string()).unwrap_or_default(),
+                end.map(|n| n.to_string()).unwrap_or_default()
+            ),
+        }
+    }
+}
+
 // Wrapping in lazy_static ensures that our regex is only compiled once
 lazy_static! {
   /// The regex that finds bookimports such as
   ///  -> `{{ #bookimport some-file.txt@some-tag }}`
+  ///  -> `{{ #bookimport some-file.txt@:10:20 }}`
   ///
   /// It will also find escaped bookimports such as
   ///  -> `\{{ #bookimport some-file.txt@some-tag }}`
@@ -160,19 +469,33 @@ lazy_static! {
     \#bookimport                       # #bookimport
     \s+                             # separating whitespace
     (?P<file>[a-zA-Z0-9\s_.\-/\\]+) # some-file.txt
-    @                               # @ symbol that denotes the name of a tag
-    (?P<tag>[a-zA-Z0-9_.\-]+)       # some-tag (alphanumeric underscores and dashes)
+    @                               # @ symbol that denotes the tag or line range that follows
+    (?:
+        (?P<tag>[a-zA-Z0-9_.\-]+)             # some-tag (alphanumeric underscores and dashes)
+      |
+        :(?P<line_start>[0-9]*):(?P<line_end>[0-9]*) # :10:20, :10: or ::20 line range
+    )
+    (?P<as_code>\s+as\s+code)?      # optional opt-in to auto-fencing this one import
     \s*\}\}                         # whitespace and closing braces
   "
   ).unwrap();
 }
 
-impl<'a> BookImport<'a> {
+impl BookImport {
     /// Parse a chapter within an mdbook for bookimport's and return them
     fn find_unescaped_bookimports(chapter: &Chapter) -> Vec<BookImport> {
+        BookImport::scan(&chapter.name, &chapter.content)
+    }
+
+    /// Parse some arbitrary piece of content (e.g. a chapter's Markdown, or a snippet that
+    /// was itself pulled in by another bookimport) for bookimport's and return them.
+    ///
+    /// `host_chapter_name` is only carried along for debugging/error messages - the content
+    /// being scanned doesn't have to come directly from that chapter.
+    fn scan(host_chapter_name: &str, content: &str) -> Vec<BookImport> {
         let mut simports = vec![];
 
-        let matches = SUPERIMPORT_REGEX.captures_iter(chapter.content.as_str());
+        let matches = SUPERIMPORT_REGEX.captures_iter(content);
 
         for capture_match in matches {
             // {{#bookimport ./fixture.css@cool-css }}
@@ -180,7 +503,7 @@ impl<'a> BookImport<'a> {
             // #{{#bookimport ./fixture.css@cool-css }}
             let full_capture = capture_match.get(0).unwrap();
 
-            let full_simport_text = &chapter.content[full_capture.start()..full_capture.end()];
+            let full_simport_text = &content[full_capture.start()..full_capture.end()];
 
             // NOTE: The backslash means that this import was escaped by the author, so
             // we don't want to replace it.
@@ -190,13 +513,27 @@ impl<'a> BookImport<'a> {
             }
 
             let file = capture_match["file"].into();
-            let tag = capture_match.get(2).unwrap();
+
+            let selector = match capture_match.name("tag") {
+                Some(tag) => ImportSelector::Tag(tag.as_str().to_string()),
+                None => ImportSelector::LineRange {
+                    start: capture_match
+                        .name("line_start")
+                        .and_then(|m| m.as_str().parse().ok()),
+                    end: capture_match
+                        .name("line_end")
+                        .and_then(|m| m.as_str().parse().ok()),
+                },
+            };
+
+            let as_code = capture_match.name("as_code").is_some();
 
             let simport = BookImport {
-                host_chapter: chapter,
+                host_chapter_name: host_chapter_name.to_string(),
                 file,
-                full_simport_text,
-                tag: &chapter.content[tag.start()..tag.end()],
+                full_simport_text: full_simport_text.to_string(),
+                selector,
+                as_code,
                 start: full_capture.start(),
                 end: full_capture.end(),
             };
@@ -208,72 +545,287 @@ impl<'a> BookImport<'a> {
     }
 }
 
-// TODO: Create TagError variants and add better error handling.
+/// Everything that can go wrong while resolving a single `{{#bookimport}}`.
+///
+/// These are deliberately narrow/data-only - `located_error` is responsible for turning one
+/// of these into a full `mdbook::errors::Error` with chapter and position context attached.
 #[derive(Debug, Fail, PartialEq)]
 enum TagError {
-    #[fail(display = "Could not find `@book start {}`", tag)]
-    #[allow(unused)] // TODO: -> Use this
-    MissingStartTag { tag: String },
+    #[fail(display = "could not find file `{}`", path)]
+    FileNotFound { path: String },
+    #[fail(display = "file `{}` is not valid UTF-8", path)]
+    InvalidUtf8 { path: String },
+    #[fail(
+        display = "could not find a start tag for `{}` (expected `@book start {}` or `ANCHOR: {}`) in {}",
+        tag, tag, tag, file
+    )]
+    MissingStartTag { tag: String, file: String },
+    #[fail(display = "could not find `{}` in {}", expected_end_marker, file)]
+    MissingEndTag {
+        tag: String,
+        file: String,
+        /// The fully rendered end marker we expected to find, in whichever marker family's
+        /// start tag was actually present, e.g. `@book end some-tag` or `ANCHOR_END: some-tag`.
+        expected_end_marker: String,
+    },
+    /// A bookimport (possibly nested inside another import) tried to pull in a
+    /// `(path, tag)` pair that's already being resolved further up the import chain.
+    #[fail(
+        display = "circular bookimport detected: `{}` at `{}` is already being resolved",
+        tag, path
+    )]
+    CircularImport { path: String, tag: String },
 }
 
-impl<'a> BookImport<'a> {
-    /// TODO: Return failure::Error instead if TagError
-    fn read_content_between_tags(&self, chapter_dir: &PathBuf) -> Result<String, TagError> {
-        debug!(
-            r#"Reading content in chapter "{}" for bookimport "{:#?}" "#,
-            self.host_chapter.name, self.full_simport_text
-        );
+/// Wrap a `TagError` with the chapter and byte offset of the `{{#bookimport}}` that
+/// triggered it, so a build failure reads like:
+///
+/// "chapter 'Intro': could not find `@book end cool-css` in ../fixture.css (bookimport starts
+/// at byte offset 20 in the chapter source)"
+///
+/// instead of a bare `TagError` (or, before this, a panic with no chapter context at all).
+fn located_error(chapter_name: &str, simport: &BookImport, cause: TagError) -> mdbook::errors::Error {
+    mdbook::errors::Error::msg(format!(
+        "chapter '{}': {} (bookimport starts at byte offset {} in the chapter source)",
+        chapter_name, cause, simport.start
+    ))
+}
 
-        let path = Path::join(&chapter_dir, &self.file);
+/// Pull the content between a named tag's start/end markers out of `content`.
+///
+/// Two marker styles are understood, so the same annotated source file works whether it's
+/// consumed by mdbook-bookimport or by mdbook's own `{{#include file:anchor}}` links:
+///  -> `@book start some-tag` / `@book end some-tag`
+///  -> `ANCHOR: some-tag` / `ANCHOR_END: some-tag`
+///
+/// If `tag`'s start marker appears more than once in `content` (e.g. a shared example file
+/// that reuses anchor names across unrelated sections), this matches against the *nearest*
+/// end marker following the first start marker, rather than the last one in the file.
+fn extract_between_tags(content: &str, tag: &str, file_display: &str) -> Result<String, TagError> {
+    let escaped_tag = regex::escape(tag);
+
+    // (start marker, end marker, human-readable end marker label used in error messages)
+    let marker_styles = &[
+        (r"@book\s+start\s+", r"@book\s+end\s+", "@book end"),
+        (r"ANCHOR:\s*", r"ANCHOR_END:\s*", "ANCHOR_END:"),
+    ];
+
+    let mut found_start_marker = None;
+
+    for (start_marker, end_marker, end_marker_label) in marker_styles {
+        let start_regex = Regex::new(&format!(r"{}{}", start_marker, escaped_tag)).unwrap();
+        if start_regex.is_match(content) {
+            found_start_marker = Some(*end_marker_label);
+        }
 
-        let content = String::from_utf8(::std::fs::read(&path).unwrap()).unwrap();
+        let marker_regex = Regex::new(&format!(
+            r"(?x)
+{start_marker}{tag}           # start marker for this tag
+.*? [\n\r]                    # rest of the start marker's line
 
-        // @book start foo <--- this line is not captured
-        // ... match all of these
-        // ... lines between the
-        // ... start and end tags
-        // @book end foo   <--- this line is not captured
-        let start_regex = Regex::new(&format!(
-            r"(?x)         # Insignificant whitespace mode (allows for comments)
-@book
-\s+                        # Separating whitespace
-start
-\s+                        # Separating whitespace
-{tag}
+(?P<content_to_import>        # everything between the start and end markers,
+  (?:.|\n|\r)*?                # including the newline right before the end marker's line -
+  [\n\r]                       # lazy, so a tag name reused later in the file doesn't get
+)                              # swallowed into this one's content
 
-.*?                        # Characters between start import tag and end of line
+.*?                           # up to the end marker's line
+{end_marker}{tag}
+",
+            start_marker = start_marker,
+            end_marker = end_marker,
+            tag = escaped_tag
+        ))
+        .unwrap();
 
-[\n\r]                     # New line right before the start import tag
+        if let Some(captures) = marker_regex.captures(content) {
+            return Ok(captures["content_to_import"].to_string());
+        }
+    }
 
-(?P<content_to_import>     # Everything in between the start and end import lines
-  (.|\n|\r)*
-)
+    // Neither marker style matched as a complete start/end pair.
+    match found_start_marker {
+        // A start marker for one of the two styles was present, so the end marker for that
+        // same style is what's actually missing (or misspelled).
+        Some(end_marker_label) => Err(TagError::MissingEndTag {
+            tag: tag.to_string(),
+            file: file_display.to_string(),
+            expected_end_marker: format!("{} {}", end_marker_label, tag),
+        }),
+        None => Err(TagError::MissingStartTag {
+            tag: tag.to_string(),
+            file: file_display.to_string(),
+        }),
+    }
+}
 
-[\n\r]                     # New line right before the end import tag
+/// Return the inclusive, 1-indexed `start..=end` slice of `content`'s lines, mirroring
+/// mdbook's own `{{#include file.rs:10:20}}` line-range syntax. Either bound may be absent
+/// to mean "from the first line" / "through the last line".
+fn extract_line_range(content: &str, start: Option<usize>, end: Option<usize>) -> String {
+    let lines: Vec<&str> = content.lines().collect();
 
-.*?                        # Characters between start of end import line and end import tag
+    let start_idx = start.map(|line| line.saturating_sub(1)).unwrap_or(0);
+    let end_idx = end.map(|line| line.min(lines.len())).unwrap_or_else(|| lines.len());
 
-@book
-\s+                        # Separating whitespace
-end
-\s+                        # Separating whitespace
-{tag}
-",
-            tag = regex::escape(self.tag)
-        ))
-        .unwrap();
+    if start_idx >= end_idx {
+        return String::new();
+    }
 
-        let captures = start_regex.captures(&content).unwrap();
+    lines[start_idx..end_idx].join("\n")
+}
+
+/// Read `path`'s contents as a `String`, reusing a previous read from `cache` when one of
+/// the other chapters being resolved concurrently has already pulled this same file in.
+fn read_file_cached(path: &Path, cache: &FileCache) -> Result<String, TagError> {
+    // Only the outer map lookup/insert takes the outer lock - the blocking `fs::read` itself
+    // happens under the per-path lock below, so one thread's disk I/O can't stall unrelated
+    // files' cache hits or reads behind a single global lock. Holding that per-path lock
+    // across the read is what makes two threads racing to read the same not-yet-cached file
+    // line up behind each other instead of both missing the cache and both hitting disk: the
+    // second thread blocks on the first's per-path lock and then finds the slot already
+    // filled in.
+    let slot = {
+        let mut cache = cache.lock().expect("file cache mutex was poisoned");
+        cache
+            .entry(path.to_path_buf())
+            .or_insert_with(|| Arc::new(Mutex::new(None)))
+            .clone()
+    };
+
+    let mut slot = slot.lock().expect("file cache slot mutex was poisoned");
+    if let Some(content) = &*slot {
+        return Ok(content.clone());
+    }
+
+    let bytes = ::std::fs::read(path).map_err(|_| TagError::FileNotFound {
+        path: path.display().to_string(),
+    })?;
+
+    let content = String::from_utf8(bytes).map_err(|_| TagError::InvalidUtf8 {
+        path: path.display().to_string(),
+    })?;
 
-        let content_between_tags = captures["content_to_import"].to_string();
+    *slot = Some(content.clone());
 
-        Ok(content_between_tags)
+    Ok(content)
+}
+
+impl BookImport {
+    /// `import_stack` tracks the `(file, tag)` pairs currently being resolved on this
+    /// import chain, so that a snippet that (transitively) tries to import itself is
+    /// reported as a `TagError::CircularImport` instead of recursing forever.
+    ///
+    /// `cache` de-duplicates the underlying file reads across every chapter being resolved,
+    /// since the same source file is often imported from more than one place in a book.
+    fn read_content_between_tags(
+        &self,
+        chapter_dir: &PathBuf,
+        import_stack: &mut Vec<(PathBuf, String)>,
+        cache: &FileCache,
+    ) -> Result<String, TagError> {
+        debug!(
+            r#"Reading content in chapter "{}" for bookimport "{:#?}" "#,
+            self.host_chapter_name, self.full_simport_text
+        );
+
+        let path = Path::join(&chapter_dir, &self.file);
+        let canonical_path = path.canonicalize().unwrap_or_else(|_| path.clone());
+
+        let stack_key = (canonical_path.clone(), self.selector.label());
+        if import_stack.contains(&stack_key) {
+            let (path, tag) = stack_key;
+            return Err(TagError::CircularImport {
+                path: path.display().to_string(),
+                tag,
+            });
+        }
+
+        // Keyed by the canonicalized path (not `path` as-written) so that two different
+        // relative spellings of the same file still share one cached read.
+        let content = read_file_cached(&canonical_path, cache)?;
+        let file_display = self.file.display().to_string();
+
+        let content_between_tags = match &self.selector {
+            // A line range bypasses the named-tag regex entirely - it's just a slice of
+            // the file's lines, so there's nothing that can be "missing".
+            ImportSelector::LineRange { start, end } => extract_line_range(&content, *start, *end),
+            ImportSelector::Tag(tag) => extract_between_tags(&content, tag, &file_display)?,
+        };
+
+        // The snippet we just pulled in might itself contain #bookimport's. Resolve those
+        // too, relative to the directory of the file we just read from (not the original
+        // chapter's directory), so nested imports compose the same way plain ones do.
+        let import_dir = path
+            .parent()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| chapter_dir.clone());
+
+        let nested_simports = BookImport::scan(&self.host_chapter_name, &content_between_tags);
+
+        import_stack.push(stack_key);
+
+        let mut resolved = content_between_tags;
+        for nested in nested_simports.iter().rev() {
+            let nested_content = match nested.read_content_between_tags(&import_dir, import_stack, cache) {
+                Ok(nested_content) => nested_content,
+                Err(err) => {
+                    // Pop our own entry before propagating so a reused `import_stack`
+                    // doesn't retain a stale entry past this call's failure.
+                    import_stack.pop();
+                    return Err(err);
+                }
+            };
+            // Splice in by `nested`'s own byte range rather than text-replacing every
+            // occurrence of `full_simport_text` - the same snippet can legitimately contain
+            // both a real bookimport and an escaped (`/{{#bookimport ...}}`) demonstration of
+            // the identical markup, and a text-based replace would corrupt the escaped one too.
+            // Iterating in reverse keeps earlier nested imports' ranges valid, same as
+            // `apply_resolved_imports` does at the chapter level.
+            resolved.replace_range(nested.start..nested.end, &nested_content);
+        }
+
+        import_stack.pop();
+
+        Ok(resolved)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use mdbook::preprocess::CmdPreprocessor;
+    use std::str::FromStr;
+
+    fn empty_cache() -> FileCache {
+        Arc::new(Mutex::new(HashMap::new()))
+    }
+
+    /// Resolve and replace every bookimport in a single chapter sequentially, mirroring
+    /// what `Bookimport::run` does per chapter once the parallel resolution pass has
+    /// produced its raw contents - without pulling in the whole `Book`/`PreprocessorContext`
+    /// machinery just to exercise one chapter.
+    fn process_chapter(
+        chapter: &mut Chapter,
+        chapter_dir: &PathBuf,
+        cache: &FileCache,
+        auto_fence_default: bool,
+    ) -> mdbook::errors::Result<()> {
+        let simports = BookImport::find_unescaped_bookimports(chapter);
+
+        let raw_contents = simports
+            .iter()
+            .map(|simport| {
+                let mut import_stack = Vec::new();
+                simport
+                    .read_content_between_tags(chapter_dir, &mut import_stack, cache)
+                    .map_err(|cause| located_error(&simport.host_chapter_name, simport, cause))
+            })
+            .collect::<mdbook::errors::Result<Vec<_>>>()?;
+
+        apply_resolved_imports(chapter, &simports, &raw_contents, auto_fence_default);
+
+        Ok(())
+    }
 
     #[test]
     fn parse_simports_from_chapter() {
@@ -282,10 +834,11 @@ mod tests {
         let simports = BookImport::find_unescaped_bookimports(&tag_import_chapter);
 
         let expected_simports = vec![BookImport {
-            host_chapter: &tag_import_chapter,
+            host_chapter_name: "Tag Import".to_string(),
             file: "./fixture.css".into(),
-            full_simport_text: "{{#bookimport ./fixture.css@cool-css }}",
-            tag: "cool-css",
+            full_simport_text: "{{#bookimport ./fixture.css@cool-css }}".to_string(),
+            selector: ImportSelector::Tag("cool-css".to_string()),
+            as_code: false,
             start: 20,
             end: 59,
         }];
@@ -311,7 +864,8 @@ mod tests {
         let chapter_dir = "book/src/test-cases/tag-import";
         let chapter_dir = format!("{}/{}", env!("CARGO_MANIFEST_DIR"), chapter_dir);
 
-        let content_between_tags = simport.read_content_between_tags(&chapter_dir.into());
+        let content_between_tags =
+            simport.read_content_between_tags(&chapter_dir.into(), &mut Vec::new(), &empty_cache());
 
         let expected_content = r#"
 .this-will-be-included {
@@ -324,10 +878,15 @@ mod tests {
 
     #[test]
     fn replace_chapter() {
-        let tag_import_chapter = make_tag_import_chapter();
-        let mut item = BookItem::Chapter(tag_import_chapter);
+        let mut tag_import_chapter = make_tag_import_chapter();
+
+        let chapter_dir: PathBuf = format!(
+            "{}/book/src/test-cases/tag-import",
+            env!("CARGO_MANIFEST_DIR")
+        )
+        .into();
 
-        process_chapter(&mut item, &"".into()).unwrap();
+        process_chapter(&mut tag_import_chapter, &chapter_dir, &empty_cache(), false).unwrap();
 
         // Spacing an indentation is intentional
         let expected_content = r#"# Tag Import
@@ -340,17 +899,14 @@ mod tests {
 
 ```
 "#;
-        match item {
-            BookItem::Chapter(tag_import_chapter) => {
-                assert_eq!(tag_import_chapter.content.as_str(), expected_content);
-            }
-            _ => panic!(""),
-        };
+        assert_eq!(tag_import_chapter.content.as_str(), expected_content);
     }
 
     #[test]
     fn replace_escaped_simport() {
-        let escaped_import_chapter = make_escaped_import_chapter();
+        let mut escaped_import_chapter = make_escaped_import_chapter();
+
+        process_chapter(&mut escaped_import_chapter, &"".into(), &empty_cache(), false).unwrap();
 
         // Spacing an indentation is intentional.
         // We're testing that the
@@ -361,16 +917,537 @@ mod tests {
 ```
 "#;
 
-        let mut item = BookItem::Chapter(escaped_import_chapter);
+        assert_eq!(escaped_import_chapter.content.as_str(), expected_content);
+    }
+
+    #[test]
+    fn nested_bookimport_is_resolved_recursively() {
+        let mut nested_import_chapter = make_nested_import_chapter();
+
+        let chapter_dir: PathBuf = format!(
+            "{}/book/src/test-cases/nested-import",
+            env!("CARGO_MANIFEST_DIR")
+        )
+        .into();
+
+        process_chapter(&mut nested_import_chapter, &chapter_dir, &empty_cache(), false).unwrap();
+
+        // The inner-most tag's content should have been spliced all the way through,
+        // with no `{{#bookimport ...}}` markers left behind.
+        assert!(!nested_import_chapter.content.contains("#bookimport"));
+        assert!(nested_import_chapter.content.contains("innermost content"));
+    }
+
+    #[test]
+    fn nested_bookimport_does_not_corrupt_an_escaped_duplicate() {
+        let mut chapter = make_nested_import_escaped_duplicate_chapter();
+
+        let chapter_dir: PathBuf = format!(
+            "{}/book/src/test-cases/nested-import-escaped-duplicate",
+            env!("CARGO_MANIFEST_DIR")
+        )
+        .into();
+
+        process_chapter(&mut chapter, &chapter_dir, &empty_cache(), false).unwrap();
+
+        // The real nested import resolves...
+        assert!(chapter.content.contains("innermost content"));
+        // ...but the escaped demonstration of the identical markup, found elsewhere in the
+        // same imported snippet, must survive byte-for-byte rather than being text-replaced
+        // along with it.
+        assert!(chapter
+            .content
+            .contains("/{{#bookimport ./inner.txt@inner }}"));
+    }
+
+    #[test]
+    fn duplicate_top_level_bookimport_resolves_both_occurrences_independently() {
+        let mut chapter = make_duplicate_top_level_import_chapter();
+
+        let chapter_dir: PathBuf = format!(
+            "{}/book/src/test-cases/duplicate-top-level-import",
+            env!("CARGO_MANIFEST_DIR")
+        )
+        .into();
+
+        process_chapter(&mut chapter, &chapter_dir, &empty_cache(), false).unwrap();
+
+        // Both occurrences of the identical `{{#bookimport ...}}` markup must resolve - a
+        // text-based replace (rather than splicing by each simport's own byte range) would
+        // rewrite both at once on the first pass and then find nothing left to replace the
+        // second time around, or corrupt the second simport's now-stale byte offsets.
+        let expected_content = "# Duplicate Top Level Import\n\nFirst occurrence:\n\n```md\n\n.this-will-be-included {\n  display: block;\n}\n\n```\n\nSecond occurrence, same markup:\n\n```md\n\n.this-will-be-included {\n  display: block;\n}\n\n```\n";
 
-        process_chapter(&mut item, &"".into()).unwrap();
+        assert_eq!(chapter.content.as_str(), expected_content);
+    }
+
+    #[test]
+    fn circular_bookimport_is_reported_not_infinite_loop() {
+        let circular_import_chapter = make_circular_import_chapter();
 
-        match item {
-            BookItem::Chapter(escaped_chapter) => {
-                assert_eq!(escaped_chapter.content.as_str(), expected_content);
+        let simport = &BookImport::find_unescaped_bookimports(&circular_import_chapter)[0];
+
+        let chapter_dir = "book/src/test-cases/circular-import";
+        let chapter_dir = format!("{}/{}", env!("CARGO_MANIFEST_DIR"), chapter_dir);
+
+        let result =
+            simport.read_content_between_tags(&chapter_dir.into(), &mut Vec::new(), &empty_cache());
+
+        match result {
+            Err(TagError::CircularImport { tag, .. }) => assert_eq!(tag, "a"),
+            other => panic!("expected a CircularImport error, got {:#?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_start_tag_is_a_structured_error() {
+        let simport = make_error_handling_simport("content.css", "does-not-exist");
+
+        let result =
+            simport.read_content_between_tags(&error_handling_dir(), &mut Vec::new(), &empty_cache());
+
+        match result {
+            Err(TagError::MissingStartTag { tag, .. }) => assert_eq!(tag, "does-not-exist"),
+            other => panic!("expected a MissingStartTag error, got {:#?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_end_tag_is_a_structured_error() {
+        let simport = make_error_handling_simport("content-missing-end.css", "lonely");
+
+        let result =
+            simport.read_content_between_tags(&error_handling_dir(), &mut Vec::new(), &empty_cache());
+
+        match result {
+            Err(TagError::MissingEndTag { tag, .. }) => assert_eq!(tag, "lonely"),
+            other => panic!("expected a MissingEndTag error, got {:#?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_file_is_a_structured_error() {
+        let simport = make_error_handling_simport("does-not-exist.css", "whatever");
+
+        let result =
+            simport.read_content_between_tags(&error_handling_dir(), &mut Vec::new(), &empty_cache());
+
+        match result {
+            Err(TagError::FileNotFound { .. }) => {}
+            other => panic!("expected a FileNotFound error, got {:#?}", other),
+        }
+    }
+
+    #[test]
+    fn anchor_style_markers_are_recognised() {
+        let simport = BookImport {
+            host_chapter_name: "Anchor Import".to_string(),
+            file: "content.rs".into(),
+            full_simport_text: "{{#bookimport content.rs@example }}".to_string(),
+            selector: ImportSelector::Tag("example".to_string()),
+            as_code: false,
+            start: 0,
+            end: 0,
+        };
+
+        let chapter_dir: PathBuf = format!(
+            "{}/book/src/test-cases/anchor-import",
+            env!("CARGO_MANIFEST_DIR")
+        )
+        .into();
+
+        let content = simport
+            .read_content_between_tags(&chapter_dir, &mut Vec::new(), &empty_cache())
+            .unwrap();
+
+        assert_eq!(content, "fn example() {\n    println!(\"hi\");\n}\n");
+    }
+
+    #[test]
+    fn repeated_tag_name_matches_the_nearest_end_marker() {
+        // The same tag name is used for two unrelated blocks in this fixture - the first
+        // block's own end marker should win, rather than the regex greedily swallowing
+        // through to the second block's end marker further down the file.
+        let simport = BookImport {
+            host_chapter_name: "Repeated Tag".to_string(),
+            file: "content.rs".into(),
+            full_simport_text: "{{#bookimport content.rs@shared }}".to_string(),
+            selector: ImportSelector::Tag("shared".to_string()),
+            as_code: false,
+            start: 0,
+            end: 0,
+        };
+
+        let chapter_dir: PathBuf = format!(
+            "{}/book/src/test-cases/repeated-tag",
+            env!("CARGO_MANIFEST_DIR")
+        )
+        .into();
+
+        let content = simport
+            .read_content_between_tags(&chapter_dir, &mut Vec::new(), &empty_cache())
+            .unwrap();
+
+        assert_eq!(content, "fn first() {\n    println!(\"first\");\n}\n");
+    }
+
+    #[test]
+    fn line_range_import_slices_inclusive_lines() {
+        let simport = BookImport {
+            host_chapter_name: "Line Range".to_string(),
+            file: "lines.txt".into(),
+            full_simport_text: "{{#bookimport lines.txt@:2:4 }}".to_string(),
+            selector: ImportSelector::LineRange {
+                start: Some(2),
+                end: Some(4),
+            },
+            as_code: false,
+            start: 0,
+            end: 0,
+        };
+
+        let chapter_dir: PathBuf = format!(
+            "{}/book/src/test-cases/line-range",
+            env!("CARGO_MANIFEST_DIR")
+        )
+        .into();
+
+        let content = simport
+            .read_content_between_tags(&chapter_dir, &mut Vec::new(), &empty_cache())
+            .unwrap();
+
+        assert_eq!(content, "line two\nline three\nline four");
+    }
+
+    #[test]
+    fn line_range_with_open_bounds_defaults_to_file_edges() {
+        let simport = BookImport {
+            host_chapter_name: "Line Range".to_string(),
+            file: "lines.txt".into(),
+            full_simport_text: "{{#bookimport lines.txt@:4: }}".to_string(),
+            selector: ImportSelector::LineRange {
+                start: Some(4),
+                end: None,
+            },
+            as_code: false,
+            start: 0,
+            end: 0,
+        };
+
+        let chapter_dir: PathBuf = format!(
+            "{}/book/src/test-cases/line-range",
+            env!("CARGO_MANIFEST_DIR")
+        )
+        .into();
+
+        let content = simport
+            .read_content_between_tags(&chapter_dir, &mut Vec::new(), &empty_cache())
+            .unwrap();
+
+        assert_eq!(content, "line four\nline five");
+    }
+
+    #[test]
+    fn auto_fence_wraps_own_line_bookimport_in_a_code_fence() {
+        let mut auto_fence_chapter = make_auto_fence_chapter();
+
+        let chapter_dir: PathBuf = format!(
+            "{}/book/src/test-cases/auto-fence",
+            env!("CARGO_MANIFEST_DIR")
+        )
+        .into();
+
+        process_chapter(&mut auto_fence_chapter, &chapter_dir, &empty_cache(), false).unwrap();
+
+        let expected_content = "# Auto Fence\n\n```rust\nfn demo() {\n    println!(\"hello\");\n}\n```\n";
+
+        assert_eq!(auto_fence_chapter.content.as_str(), expected_content);
+    }
+
+    #[test]
+    fn auto_fence_trims_the_snippet_s_common_leading_indentation() {
+        let mut auto_fence_indent_chapter = make_auto_fence_indent_chapter();
+
+        let chapter_dir: PathBuf = format!(
+            "{}/book/src/test-cases/auto-fence-indent",
+            env!("CARGO_MANIFEST_DIR")
+        )
+        .into();
+
+        process_chapter(
+            &mut auto_fence_indent_chapter,
+            &chapter_dir,
+            &empty_cache(),
+            false,
+        )
+        .unwrap();
+
+        let expected_content = "# Auto Fence Indent\n\n```rust\nfn demo() {\n    println!(\"hello\");\n}\n```\n";
+
+        assert_eq!(auto_fence_indent_chapter.content.as_str(), expected_content);
+    }
+
+    #[test]
+    fn auto_fence_does_not_double_wrap_a_bookimport_already_inside_a_fence() {
+        let mut already_fenced_chapter = make_already_fenced_chapter();
+
+        let chapter_dir: PathBuf = format!(
+            "{}/book/src/test-cases/auto-fence-already-fenced",
+            env!("CARGO_MANIFEST_DIR")
+        )
+        .into();
+
+        process_chapter(
+            &mut already_fenced_chapter,
+            &chapter_dir,
+            &empty_cache(),
+            false,
+        )
+        .unwrap();
+
+        let expected_content = "# Already Fenced\n\n```rust\nfn demo() {\n    println!(\"hello\");\n}\n```\n";
+
+        assert_eq!(already_fenced_chapter.content.as_str(), expected_content);
+    }
+
+    #[test]
+    fn book_wide_auto_fence_wraps_a_bookimport_with_no_as_code_flag() {
+        let mut auto_fence_default_chapter = make_auto_fence_default_chapter();
+
+        let chapter_dir: PathBuf = format!(
+            "{}/book/src/test-cases/auto-fence-default",
+            env!("CARGO_MANIFEST_DIR")
+        )
+        .into();
+
+        // `auto_fence_default: true` here stands in for a book-wide
+        // `preprocessor.bookimport.auto-fence = true`; the bookimport itself has no
+        // `as code` flag.
+        process_chapter(
+            &mut auto_fence_default_chapter,
+            &chapter_dir,
+            &empty_cache(),
+            true,
+        )
+        .unwrap();
+
+        let expected_content = "# Auto Fence Default\n\n```rust\nfn demo() {\n    println!(\"hello\");\n}\n```\n";
+
+        assert_eq!(auto_fence_default_chapter.content.as_str(), expected_content);
+    }
+
+    #[test]
+    fn repeated_file_reads_are_served_from_cache() {
+        let cache = empty_cache();
+
+        let chapter_dir = "book/src/test-cases/tag-import";
+        let chapter_dir: PathBuf = format!("{}/{}", env!("CARGO_MANIFEST_DIR"), chapter_dir).into();
+        let path = chapter_dir.join("fixture.css");
+
+        let first_read = read_file_cached(&path, &cache);
+        let second_read = read_file_cached(&path, &cache);
+
+        assert_eq!(first_read, second_read);
+        assert_eq!(cache.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn allowed_renderers_reads_the_configured_list() {
+        let config = mdbook::Config::from_str("[preprocessor.bookimport]\nrenderer = [\"html\"]\n")
+            .unwrap();
+
+        assert_eq!(allowed_renderers(&config), Some(vec!["html".to_string()]));
+    }
+
+    #[test]
+    fn allowed_renderers_is_unrestricted_when_the_key_is_absent() {
+        let config = mdbook::Config::from_str("[preprocessor.bookimport]\n").unwrap();
+
+        assert_eq!(allowed_renderers(&config), None);
+    }
+
+    #[test]
+    fn auto_fence_enabled_reads_the_book_wide_flag() {
+        let config =
+            mdbook::Config::from_str("[preprocessor.bookimport]\nauto-fence = true\n").unwrap();
+
+        assert!(auto_fence_enabled(&config));
+    }
+
+    #[test]
+    fn auto_fence_enabled_defaults_to_off() {
+        let config = mdbook::Config::from_str("[preprocessor.bookimport]\n").unwrap();
+
+        assert!(!auto_fence_enabled(&config));
+    }
+
+    #[test]
+    fn run_passes_the_book_through_untouched_when_the_renderer_is_not_allowed() {
+        // Mirrors the JSON that mdbook itself pipes over stdin: a `(PreprocessorContext,
+        // Book)` pair, fed through the same `CmdPreprocessor::parse_input` our `main.rs`
+        // uses, since `PreprocessorContext` can't be built directly from another crate.
+        let input = serde_json::json!([
+            {
+                "root": "/tmp/book",
+                "config": {
+                    "book": { "src": "src" },
+                    "preprocessor": { "bookimport": { "renderer": ["html"] } }
+                },
+                "renderer": "epub",
+                "mdbook_version": "0.4.21"
+            },
+            {
+                "sections": [
+                    {
+                        "Chapter": {
+                            "name": "Untouched",
+                            "content": "{{#bookimport ./fixture.css@cool-css }}",
+                            "number": null,
+                            "sub_items": [],
+                            "path": "untouched.md",
+                            "source_path": "untouched.md",
+                            "parent_names": []
+                        }
+                    }
+                ],
+                "__non_exhaustive": null
             }
-            _ => panic!(""),
+        ])
+        .to_string();
+
+        let (ctx, book) = CmdPreprocessor::parse_input(input.as_bytes()).unwrap();
+
+        let processed = Bookimport.run(&ctx, book).unwrap();
+
+        let chapter_content = match &processed.sections[0] {
+            BookItem::Chapter(chapter) => chapter.content.clone(),
+            _ => panic!("expected the untouched chapter to still be a Chapter"),
         };
+
+        // The `epub` renderer isn't in the configured allowlist, so `run` should hand the
+        // book back with its #bookimport still unresolved.
+        assert_eq!(chapter_content, "{{#bookimport ./fixture.css@cool-css }}");
+    }
+
+    #[test]
+    fn run_resolves_bookimports_across_top_level_and_nested_chapters() {
+        // Drives the real `collect_chapter_imports`/`chapter_at_mut`/parallel-apply path in
+        // `run`, rather than the hand-written `process_chapter` helper the other tests use -
+        // that helper never goes through the path-indexed recursion into `sub_items` or the
+        // chapter-index/simport-index bookkeeping that ties parallel results back to the
+        // right chapter, so it wouldn't catch a chapter ending up with another chapter's
+        // resolved content or a nested chapter being skipped.
+        let tag_import_readme = include_str!("../book/src/test-cases/tag-import/README.md");
+        let nested_import_readme = include_str!("../book/src/test-cases/nested-import/README.md");
+
+        let input = serde_json::json!([
+            {
+                "root": env!("CARGO_MANIFEST_DIR"),
+                "config": {
+                    "book": { "src": "book/src/test-cases" }
+                },
+                "renderer": "html",
+                "mdbook_version": "0.4.21"
+            },
+            {
+                "sections": [
+                    {
+                        "Chapter": {
+                            "name": "Tag Import",
+                            "content": tag_import_readme,
+                            "number": null,
+                            "sub_items": [],
+                            "path": "tag-import/README.md",
+                            "source_path": "tag-import/README.md",
+                            "parent_names": []
+                        }
+                    },
+                    {
+                        "Chapter": {
+                            "name": "Parent",
+                            "content": "# Parent\n\nNo imports of its own, just a nested chapter below.\n",
+                            "number": null,
+                            "sub_items": [
+                                {
+                                    "Chapter": {
+                                        "name": "Nested Import",
+                                        "content": nested_import_readme,
+                                        "number": null,
+                                        "sub_items": [],
+                                        "path": "nested-import/README.md",
+                                        "source_path": "nested-import/README.md",
+                                        "parent_names": ["Parent"]
+                                    }
+                                }
+                            ],
+                            "path": "parent.md",
+                            "source_path": "parent.md",
+                            "parent_names": []
+                        }
+                    }
+                ],
+                "__non_exhaustive": null
+            }
+        ])
+        .to_string();
+
+        let (ctx, book) = CmdPreprocessor::parse_input(input.as_bytes()).unwrap();
+
+        let processed = Bookimport.run(&ctx, book).unwrap();
+
+        let tag_import_content = match &processed.sections[0] {
+            BookItem::Chapter(chapter) => chapter.content.clone(),
+            _ => panic!("expected the first section to still be a Chapter"),
+        };
+        let (parent_content, nested_content) = match &processed.sections[1] {
+            BookItem::Chapter(parent) => {
+                let nested = match &parent.sub_items[0] {
+                    BookItem::Chapter(nested) => nested.content.clone(),
+                    _ => panic!("expected the nested section to still be a Chapter"),
+                };
+                (parent.content.clone(), nested)
+            }
+            _ => panic!("expected the second section to still be a Chapter"),
+        };
+
+        // Each chapter's own bookimport should be resolved with its own content - a mix-up
+        // between the two chapters' indices would leave one of them with the wrong (or a
+        // still-unresolved) import.
+        assert!(tag_import_content.contains(".this-will-be-included"));
+        assert!(!tag_import_content.contains("#bookimport"));
+
+        assert_eq!(
+            parent_content,
+            "# Parent\n\nNo imports of its own, just a nested chapter below.\n"
+        );
+
+        // The nested chapter (reached through `sub_items`) must be resolved too, recursively
+        // through its own nested bookimport.
+        assert!(!nested_content.contains("#bookimport"));
+        assert!(nested_content.contains("innermost content"));
+    }
+
+    // The directory that holds our error-handling test case fixtures.
+    fn error_handling_dir() -> PathBuf {
+        format!(
+            "{}/book/src/test-cases/error-handling",
+            env!("CARGO_MANIFEST_DIR")
+        )
+        .into()
+    }
+
+    // A BookImport pointed at one of the error-handling fixtures, built by hand since these
+    // tests care about `read_content_between_tags`'s error handling rather than parsing.
+    fn make_error_handling_simport(file: &str, tag: &str) -> BookImport {
+        BookImport {
+            host_chapter_name: "Error Handling".to_string(),
+            file: file.into(),
+            full_simport_text: format!("{{{{#bookimport {}@{} }}}}", file, tag),
+            selector: ImportSelector::Tag(tag.to_string()),
+            as_code: false,
+            start: 0,
+            end: 0,
+        }
     }
 
     // Create a chapter to represent our tag-import test case in the /book
@@ -402,4 +1479,111 @@ mod tests {
 
         tag_import_chapter
     }
+
+    // Create a chapter to represent our nested-import test case, where the file being
+    // imported itself contains a #bookimport.
+    fn make_nested_import_chapter() -> Chapter {
+        let chapter = "book/src/test-cases/nested-import/README.md";
+
+        Chapter::new(
+            "Nested Import",
+            include_str!("../book/src/test-cases/nested-import/README.md").to_string(),
+            &format!("{}/{}", env!("CARGO_MANIFEST_DIR"), chapter),
+            vec![],
+        )
+    }
+
+    // Create a chapter for a nested-import test case whose imported snippet contains both
+    // a real `{{#bookimport}}` and an escaped demonstration of the identical markup.
+    fn make_nested_import_escaped_duplicate_chapter() -> Chapter {
+        let chapter = "book/src/test-cases/nested-import-escaped-duplicate/README.md";
+
+        Chapter::new(
+            "Nested Import With Escaped Duplicate",
+            include_str!("../book/src/test-cases/nested-import-escaped-duplicate/README.md")
+                .to_string(),
+            &format!("{}/{}", env!("CARGO_MANIFEST_DIR"), chapter),
+            vec![],
+        )
+    }
+
+    // Create a chapter to represent our duplicate-top-level-import test case, where the
+    // exact same `{{#bookimport ...}}` markup appears twice in one chapter's own content.
+    fn make_duplicate_top_level_import_chapter() -> Chapter {
+        let chapter = "book/src/test-cases/duplicate-top-level-import/README.md";
+
+        Chapter::new(
+            "Duplicate Top Level Import",
+            include_str!("../book/src/test-cases/duplicate-top-level-import/README.md")
+                .to_string(),
+            &format!("{}/{}", env!("CARGO_MANIFEST_DIR"), chapter),
+            vec![],
+        )
+    }
+
+    // Create a chapter to represent our circular-import test case, where two files import
+    // each other's tag forever.
+    fn make_circular_import_chapter() -> Chapter {
+        let chapter = "book/src/test-cases/circular-import/README.md";
+
+        Chapter::new(
+            "Circular Import",
+            include_str!("../book/src/test-cases/circular-import/README.md").to_string(),
+            &format!("{}/{}", env!("CARGO_MANIFEST_DIR"), chapter),
+            vec![],
+        )
+    }
+
+    // Create a chapter to represent our auto-fence test case, where a bookimport opts into
+    // code-fencing via `as code` instead of relying on the book-wide config flag.
+    fn make_auto_fence_chapter() -> Chapter {
+        let chapter = "book/src/test-cases/auto-fence/README.md";
+
+        Chapter::new(
+            "Auto Fence",
+            include_str!("../book/src/test-cases/auto-fence/README.md").to_string(),
+            &format!("{}/{}", env!("CARGO_MANIFEST_DIR"), chapter),
+            vec![],
+        )
+    }
+
+    // Create a chapter to represent our auto-fence-indent test case, where the imported
+    // snippet has common leading indentation that needs trimming before it's fenced.
+    fn make_auto_fence_indent_chapter() -> Chapter {
+        let chapter = "book/src/test-cases/auto-fence-indent/README.md";
+
+        Chapter::new(
+            "Auto Fence Indent",
+            include_str!("../book/src/test-cases/auto-fence-indent/README.md").to_string(),
+            &format!("{}/{}", env!("CARGO_MANIFEST_DIR"), chapter),
+            vec![],
+        )
+    }
+
+    // Create a chapter to represent our auto-fence-already-fenced test case, where the
+    // bookimport already sits inside a manual fence in the chapter's own Markdown.
+    fn make_already_fenced_chapter() -> Chapter {
+        let chapter = "book/src/test-cases/auto-fence-already-fenced/README.md";
+
+        Chapter::new(
+            "Already Fenced",
+            include_str!("../book/src/test-cases/auto-fence-already-fenced/README.md")
+                .to_string(),
+            &format!("{}/{}", env!("CARGO_MANIFEST_DIR"), chapter),
+            vec![],
+        )
+    }
+
+    // Create a chapter to represent our auto-fence-default test case, where the bookimport
+    // has no `as code` flag and relies entirely on the book-wide auto-fence setting.
+    fn make_auto_fence_default_chapter() -> Chapter {
+        let chapter = "book/src/test-cases/auto-fence-default/README.md";
+
+        Chapter::new(
+            "Auto Fence Default",
+            include_str!("../book/src/test-cases/auto-fence-default/README.md").to_string(),
+            &format!("{}/{}", env!("CARGO_MANIFEST_DIR"), chapter),
+            vec![],
+        )
+    }
 }